@@ -91,4 +91,12 @@ fn setup(
   for obj in objs {
     commands.spawn().insert(obj.typed::<ObjectAsset>());
   }
+
+  // Load and spawn scene blueprints.
+  let blueprints = asset_server.load_folder("blueprints").unwrap();
+  for blueprint in blueprints {
+    commands
+      .spawn_bundle((Transform::identity(), GlobalTransform::identity()))
+      .insert(blueprint.typed::<SceneBlueprint>());
+  }
 }
@@ -1,31 +1,29 @@
 use bevy::{
   prelude::*,
+  ecs::system::{Command, CommandQueue, EntityCommands},
+  gltf::{Gltf, GltfMesh},
   reflect::TypeUuid,
   render::{
+    draw::{Draw, RenderCommand},
     mesh::shape,
-    pipeline::{PipelineDescriptor, RenderPipeline},
-    render_graph::{base, AssetRenderResourcesNode, RenderGraph},
-    renderer::{RenderResource, RenderResourceIterator, RenderResources},
+    pipeline::{
+      draw_render_pipelines_system, InputStepMode, PipelineDescriptor, PipelineLayout,
+      RenderPipeline, VertexBufferDescriptor,
+    },
+    render_graph::{base, AssetRenderResourcesNode, Node, RenderGraph, ResourceSlots},
+    renderer::{
+      BufferId, BufferInfo, BufferUsage, RenderContext, RenderResource,
+      RenderResourceIterator, RenderResources,
+    },
     shader::ShaderStages,
+    stage,
   },
 };
 use bevy_asset_ron::*;
 
-use std::sync::{Arc, RwLock};
 use serde::Deserialize;
-use indexmap::{IndexMap, IndexSet};
-
-lazy_static::lazy_static! {
-  static ref NAME_TO_INDEX: Arc<RwLock<IndexSet<String>>> = {
-    Arc::new(RwLock::new(IndexSet::new()))
-  };
-}
-
-fn name_to_idx(name: &str) -> usize {
-  let (idx, _) = NAME_TO_INDEX.write().unwrap().insert_full(name.into());
-  idx
-}
-
+use indexmap::IndexMap;
+use std::collections::HashMap;
 
 #[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum CapsuleUvProfile {
@@ -71,35 +69,59 @@ pub enum ObjectShape {
     ring_radius: f32,
     subdivisions_segments: usize,
     subdivisions_sides: usize,
-  }
+  },
+  /// A mesh pulled out of an artist-authored GLTF/GLB file, by mesh index.
+  Gltf {
+    path: String,
+    mesh_index: usize,
+  },
+  /// A raw mesh file, loaded directly as a `Mesh`.
+  Mesh(String),
 }
 
 impl ObjectShape {
-  fn mesh(&self) -> Mesh {
-    match *self {
+  /// `None` for `Gltf`/`Mesh`, resolved asynchronously via `loading_mesh` instead.
+  fn mesh(&self) -> Option<Mesh> {
+    match self {
       ObjectShape::Box(x, y, z) =>
-        Mesh::from(shape::Box::new(x, y, z)),
+        Some(Mesh::from(shape::Box::new(*x, *y, *z))),
       ObjectShape::Capsule { radius, rings, depth, latitudes, longitudes, uv_profile } =>
-        Mesh::from(shape::Capsule {
-          radius, rings, depth, latitudes, longitudes,
-          uv_profile: uv_profile.into(),
-        }),
+        Some(Mesh::from(shape::Capsule {
+          radius: *radius, rings: *rings, depth: *depth, latitudes: *latitudes, longitudes: *longitudes,
+          uv_profile: (*uv_profile).into(),
+        })),
       ObjectShape::Cube(size) =>
-        Mesh::from(shape::Cube::new(size)),
+        Some(Mesh::from(shape::Cube::new(*size))),
       ObjectShape::Icosphere { radius, subdivisions } =>
-        Mesh::from(shape::Icosphere {
-          radius, subdivisions
-        }),
+        Some(Mesh::from(shape::Icosphere {
+          radius: *radius, subdivisions: *subdivisions
+        })),
       ObjectShape::Plane(size) =>
-        Mesh::from(shape::Plane { size }),
+        Some(Mesh::from(shape::Plane { size: *size })),
       ObjectShape::Quad { size, flip } =>
-        Mesh::from(shape::Quad {
-          size, flip
-        }),
+        Some(Mesh::from(shape::Quad {
+          size: *size, flip: *flip
+        })),
       ObjectShape::Torus { radius, ring_radius, subdivisions_segments, subdivisions_sides } =>
-        Mesh::from(shape::Torus {
-          radius, ring_radius, subdivisions_segments, subdivisions_sides
+        Some(Mesh::from(shape::Torus {
+          radius: *radius, ring_radius: *ring_radius, subdivisions_segments: *subdivisions_segments, subdivisions_sides: *subdivisions_sides
+        })),
+      ObjectShape::Gltf { .. } | ObjectShape::Mesh(_) =>
+        None,
+    }
+  }
+
+  /// `None` for the built-in primitive shapes, which don't need asset loading.
+  fn loading_mesh(&self, asset_server: &AssetServer) -> Option<LoadingMesh> {
+    match self {
+      ObjectShape::Gltf { path, mesh_index } =>
+        Some(LoadingMesh::Gltf {
+          gltf: asset_server.load(path.as_str()),
+          mesh_index: *mesh_index,
         }),
+      ObjectShape::Mesh(path) =>
+        Some(LoadingMesh::Mesh(asset_server.load(path.as_str()))),
+      _ => None,
     }
   }
 }
@@ -126,6 +148,13 @@ impl MaterialPipeline {
 pub enum MaterialResourceType {
   Color,
   Texture,
+  /// Filled in every frame by `update_time_uniforms`; not present in the `.material` file.
+  Time,
+  Float,
+  Vec2,
+  Vec3,
+  Vec4,
+  Int,
 }
 
 #[derive(Deserialize, TypeUuid, Clone, Debug, PartialEq)]
@@ -140,12 +169,41 @@ impl MaterialType {
   fn loading(&self, asset_server: &AssetServer) -> LoadingPipeline {
     self.pipeline.loading(asset_server)
   }
+
+  /// Warns about `.material` resources not declared by this type's schema.
+  fn validate(&self, settings: &MaterialSettings) {
+    for name in settings.resources.keys() {
+      if !self.resource_types.contains_key(name) {
+        warn!("MaterialType {:?}: material supplies unknown resource {:?}", self.name, name);
+      }
+    }
+  }
 }
 
 #[derive(Deserialize, Clone, Debug, PartialEq)]
 pub enum MaterialResource {
   Color(Color),
   Texture(String),
+  Float(f32),
+  Vec2([f32; 2]),
+  Vec3([f32; 3]),
+  Vec4([f32; 4]),
+  Int(i32),
+}
+
+impl MaterialResource {
+  fn matches(&self, resource_type: &MaterialResourceType) -> bool {
+    matches!(
+      (self, resource_type),
+      (MaterialResource::Color(_), MaterialResourceType::Color)
+        | (MaterialResource::Texture(_), MaterialResourceType::Texture)
+        | (MaterialResource::Float(_), MaterialResourceType::Float)
+        | (MaterialResource::Vec2(_), MaterialResourceType::Vec2)
+        | (MaterialResource::Vec3(_), MaterialResourceType::Vec3)
+        | (MaterialResource::Vec4(_), MaterialResourceType::Vec4)
+        | (MaterialResource::Int(_), MaterialResourceType::Int)
+    )
+  }
 }
 
 #[derive(Deserialize, TypeUuid, Clone, Debug, PartialEq)]
@@ -170,6 +228,67 @@ pub struct ObjectAsset {
   shape: ObjectShape,
   translation: [f32; 3],
   material: MaterialSettings,
+  /// Per-instance translations; non-empty means draw `instances.len()` GPU-instanced copies.
+  #[serde(default)]
+  instances: Vec<[f32; 3]>,
+  /// Optional per-instance tint, indexed in parallel with `instances`; defaults to `Color::WHITE`.
+  #[serde(default)]
+  instance_colors: Vec<Color>,
+}
+
+/// An extra component a blueprint child (or root) can carry beyond shape/transform/material.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub enum BlueprintComponent {
+  Name(String),
+  Tag(String),
+}
+
+impl BlueprintComponent {
+  fn insert(&self, entity: &mut EntityCommands) {
+    match self {
+      BlueprintComponent::Name(name) => { entity.insert(Name::new(name.clone())); },
+      BlueprintComponent::Tag(tag) => { entity.insert(Tag(tag.clone())); },
+    }
+  }
+}
+
+pub struct Tag(pub String);
+
+/// One child of a `SceneBlueprint`: a named object authored inline, like an `.obj` file.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct BlueprintChild {
+  name: Option<String>,
+  shape: ObjectShape,
+  translation: [f32; 3],
+  material: MaterialSettings,
+  /// Extra components to insert on this child's entity once it's spawned.
+  #[serde(default)]
+  extra: Vec<BlueprintComponent>,
+}
+
+impl BlueprintChild {
+  /// The `ObjectAsset` to spawn for this child, offset by the blueprint instance's root.
+  fn object_asset(&self, root_translation: Vec3) -> ObjectAsset {
+    let translation: Vec3 = Vec3::from(self.translation) + root_translation;
+    ObjectAsset {
+      shape: self.shape.clone(),
+      translation: translation.into(),
+      material: self.material.clone(),
+      instances: Vec::new(),
+      instance_colors: Vec::new(),
+    }
+  }
+}
+
+/// A named hierarchy of child objects that can be spawned as a whole.
+#[derive(Deserialize, TypeUuid, Clone, Debug, PartialEq)]
+#[uuid = "6a6cf0da-70fb-11ec-9e1e-63a68a508f3f"]
+pub struct SceneBlueprint {
+  name: String,
+  children: Vec<BlueprintChild>,
+  /// Extra components to insert on the blueprint's root entity.
+  #[serde(default)]
+  extra: Vec<BlueprintComponent>,
 }
 
 #[derive(TypeUuid, Default)]
@@ -185,8 +304,8 @@ impl CustomMaterial {
     }
   }
 
-  pub fn insert<T: 'static + RenderResource + Send + Sync>(&mut self, name: &str, resource: T) {
-    let idx = name_to_idx(name);
+  /// `idx` is the resource's binding index, from its `MaterialType`'s schema order.
+  pub fn insert<T: 'static + RenderResource + Send + Sync>(&mut self, idx: usize, name: &str, resource: T) {
     self.resources.insert(idx, (name.into(), Box::new(resource)));
   }
 }
@@ -211,6 +330,160 @@ impl RenderResources for CustomMaterial {
   }
 }
 
+/// Number of `f32`s packed per instance row: `position.xyz`, `scale`, `color.rgba`.
+const INSTANCE_DATA_STRIDE: usize = 8;
+
+/// Packed per-instance `[position.xyz, scale, color.rgba]` rows for GPU instancing.
+pub struct InstanceData {
+  buffer: Vec<u8>,
+  count: usize,
+}
+
+impl InstanceData {
+  /// `None` if the object has no instances (the common, non-instanced case).
+  fn from_object(obj: &ObjectAsset) -> Option<Self> {
+    if obj.instances.is_empty() {
+      return None;
+    }
+    let mut buffer = Vec::with_capacity(obj.instances.len() * INSTANCE_DATA_STRIDE * 4);
+    for (i, translation) in obj.instances.iter().enumerate() {
+      let color = obj.instance_colors.get(i).copied().unwrap_or(Color::WHITE);
+      let row: [f32; INSTANCE_DATA_STRIDE] = [
+        translation[0], translation[1], translation[2], 1.0,
+        color.r(), color.g(), color.b(), color.a(),
+      ];
+      for value in row {
+        buffer.extend_from_slice(&value.to_le_bytes());
+      }
+    }
+    Some(Self { buffer, count: obj.instances.len() })
+  }
+
+  /// The instance count the draw call for this entity should use.
+  pub fn instance_count(&self) -> usize {
+    self.count
+  }
+}
+
+/// Naming convention a `.vert` shader uses to mark an input that comes from `InstanceData`
+/// at vertex-rate `Instance` rather than from the mesh's own per-vertex buffer, e.g.
+/// `layout(location = 3) in vec4 Vertex_Instance_Position;`.
+const INSTANCE_ATTRIBUTE_PREFIX: &str = "Vertex_Instance_";
+
+/// Shader reflection has no notion of instancing, so it lumps every vertex input
+/// (mesh and per-instance alike) into a single vertex-rate buffer descriptor. Split any
+/// `Vertex_Instance_*` attributes back out into their own buffer, stepped per-instance,
+/// so `InstanceData`'s packed rows actually land on the attributes that expect them.
+fn split_instance_buffer_descriptor(layout: &mut PipelineLayout) {
+  let mesh_buffer = match layout.vertex_buffer_descriptors.first_mut() {
+    Some(buffer) => buffer,
+    None => return,
+  };
+  let (mut instance_attributes, mesh_attributes): (Vec<_>, Vec<_>) = mesh_buffer.attributes
+    .drain(..)
+    .partition(|attribute| attribute.name.starts_with(INSTANCE_ATTRIBUTE_PREFIX));
+  mesh_buffer.attributes = mesh_attributes;
+  if instance_attributes.is_empty() {
+    return;
+  }
+  let mut offset = 0;
+  for attribute in instance_attributes.iter_mut() {
+    attribute.offset = offset;
+    offset += attribute.format.get_size();
+  }
+  layout.vertex_buffer_descriptors.push(VertexBufferDescriptor {
+    name: "InstanceData".into(),
+    stride: INSTANCE_DATA_STRIDE as u64 * 4,
+    step_mode: InputStepMode::Instance,
+    attributes: instance_attributes,
+  });
+}
+
+/// GPU buffers uploaded for instanced entities, keyed by entity.
+#[derive(Default)]
+struct InstanceBuffers(HashMap<Entity, (BufferId, Vec<u8>)>);
+
+/// Uploads each instanced entity's packed buffer, re-uploading only when it changed.
+#[derive(Default)]
+struct InstanceBufferNode;
+
+impl Node for InstanceBufferNode {
+  fn update(
+    &mut self,
+    world: &World,
+    render_context: &mut dyn RenderContext,
+    _input: &ResourceSlots,
+    _output: &mut ResourceSlots,
+  ) {
+    let world_cell = world.cell();
+    let mut instance_buffers = world_cell.get_resource_mut::<InstanceBuffers>()
+      .expect("InstanceBuffers resource not initialized");
+    let mut query = world_cell.query::<(Entity, &InstanceData)>();
+    for (entity, instance_data) in query.iter(&world_cell) {
+      let up_to_date = matches!(
+        instance_buffers.0.get(&entity),
+        Some((_, uploaded)) if uploaded == &instance_data.buffer
+      );
+      if up_to_date {
+        continue;
+      }
+      if let Some((old_buffer, _)) = instance_buffers.0.remove(&entity) {
+        render_context.resources().remove_buffer(old_buffer);
+      }
+      let buffer = render_context.resources().create_buffer_with_data(
+        BufferInfo {
+          size: instance_data.buffer.len(),
+          buffer_usage: BufferUsage::VERTEX,
+          mapped_at_creation: false,
+        },
+        &instance_data.buffer,
+      );
+      instance_buffers.0.insert(entity, (buffer, instance_data.buffer.clone()));
+    }
+    drop(query);
+
+    // Reclaim buffers for entities that lost InstanceData (update_objects) or
+    // were despawned entirely (e.g. watch_blueprints's reload), neither of
+    // which shows up in the query above.
+    instance_buffers.0.retain(|entity, (buffer, _)| {
+      let still_instanced = world.get_entity(*entity)
+        .map_or(false, |entity| entity.contains::<InstanceData>());
+      if !still_instanced {
+        render_context.resources().remove_buffer(*buffer);
+      }
+      still_instanced
+    });
+  }
+}
+
+/// Binds each instanced entity's uploaded buffer at vertex slot 1 and
+/// rewrites its `DrawIndexed` command to draw `InstanceData::instance_count()` copies.
+fn apply_instance_draw_commands(
+  instance_buffers: Res<InstanceBuffers>,
+  mut query: Query<(Entity, &InstanceData, &mut Draw)>,
+) {
+  for (entity, instance_data, mut draw) in query.iter_mut() {
+    let buffer = match instance_buffers.0.get(&entity) {
+      Some((buffer, _)) => *buffer,
+      None => continue, // Not uploaded yet.
+    };
+    for command in draw.render_commands.iter_mut() {
+      if let RenderCommand::DrawIndexed { instances, .. } = command {
+        *instances = 0..instance_data.instance_count() as u32;
+      }
+    }
+    // Bind ahead of the draw command so the draw actually sees the buffer.
+    let draw_idx = draw.render_commands.iter()
+      .position(|command| matches!(command, RenderCommand::DrawIndexed { .. }))
+      .unwrap_or(draw.render_commands.len());
+    draw.render_commands.insert(draw_idx, RenderCommand::SetVertexBuffer {
+      slot: 1,
+      buffer,
+      offset: 0,
+    });
+  }
+}
+
 struct UpdateObject;
 struct LoadedPipeline {
   render_pipeline: RenderPipeline,
@@ -225,13 +498,28 @@ struct LoadingPipeline {
   fragment: Option<Handle<Shader>>,
 }
 
+/// Waiting on an external mesh asset (GLTF or raw mesh file) to finish loading.
+enum LoadingMesh {
+  Gltf {
+    gltf: Handle<Gltf>,
+    mesh_index: usize,
+  },
+  Mesh(Handle<Mesh>),
+}
+
+/// Ready to be used by `spawn_object` or `update_mesh_handle`.
+struct LoadedMesh(Handle<Mesh>);
+
+/// An already-spawned entity waiting on `LoadedMesh` to swap its `Handle<Mesh>`.
+struct UpdatingMesh;
+
 fn loading_material_type(
-  query: Query<(Entity, &LoadingMaterialType)>,
+  query: Query<(Entity, &LoadingMaterialType, &ObjectAsset)>,
   material_types: Res<Assets<MaterialType>>,
   mut commands: Commands,
   asset_server: Res<AssetServer>,
 ) {
-  for (entity, loading) in query.iter() {
+  for (entity, loading, obj) in query.iter() {
     // Check if the material type definition is loaded.
     let material_type = match material_types.get(&loading.material_type) {
       Some(material_type) => material_type,
@@ -242,6 +530,9 @@ fn loading_material_type(
     };
 
     eprintln!("MaterialType loaded: {:#?}", material_type);
+    // Warn about typo'd resource names against the declared schema now;
+    // missing or mistyped resources get fallbacks in `sync_material_resources`.
+    material_type.validate(&obj.material);
     commands.entity(entity)
       .remove::<LoadingMaterialType>()
       .insert(material_type.loading(&asset_server))
@@ -275,6 +566,23 @@ fn loading_pipeline(
       fragment: loading.fragment.clone(),
     }));
 
+    // Reflect the shaders' own layout up front (the same reflection bevy's pipeline
+    // compiler falls back to when `layout` is `None`), then pull any `Vertex_Instance_*`
+    // attributes out into their own instance-stepped buffer at slot 1. Without this,
+    // the pipeline never learns a second, per-instance vertex buffer exists.
+    let pipeline = pipelines.get_mut(&pipeline_handle).expect("pipeline just inserted");
+    let vertex_shader = shaders.get(&loading.vertex).expect("vertex shader just confirmed loaded");
+    let mut shader_layouts = vec![
+      vertex_shader.reflect_layout(true).expect("failed to reflect vertex shader layout"),
+    ];
+    if let Some(fragment_handle) = &loading.fragment {
+      let fragment_shader = shaders.get(fragment_handle).expect("fragment shader just confirmed loaded");
+      shader_layouts.push(fragment_shader.reflect_layout(true).expect("failed to reflect fragment shader layout"));
+    }
+    let mut layout = PipelineLayout::from_shader_layouts(&mut shader_layouts);
+    split_instance_buffer_descriptor(&mut layout);
+    pipeline.layout = Some(layout);
+
     commands.entity(entity)
       .remove::<LoadingPipeline>()
       .insert(LoadedPipeline {
@@ -283,34 +591,144 @@ fn loading_pipeline(
   }
 }
 
+fn loading_mesh(
+  query: Query<(Entity, &LoadingMesh)>,
+  gltfs: Res<Assets<Gltf>>,
+  gltf_meshes: Res<Assets<GltfMesh>>,
+  raw_meshes: Res<Assets<Mesh>>,
+  mut commands: Commands,
+) {
+  for (entity, loading) in query.iter() {
+    let mesh = match loading {
+      LoadingMesh::Gltf { gltf, mesh_index } => {
+        let gltf = match gltfs.get(gltf) {
+          Some(gltf) => gltf,
+          None => continue, // Still loading.
+        };
+        let gltf_mesh = match gltf.meshes.get(*mesh_index) {
+          Some(handle) => match gltf_meshes.get(handle) {
+            Some(gltf_mesh) => gltf_mesh,
+            None => continue, // Still loading.
+          },
+          None => {
+            error!("Gltf mesh_index {} out of range, giving up", mesh_index);
+            commands.entity(entity).remove::<LoadingMesh>();
+            continue;
+          }
+        };
+        match gltf_mesh.primitives.get(0) {
+          Some(primitive) => primitive.mesh.clone(),
+          None => {
+            error!("Gltf mesh has no primitives, giving up");
+            commands.entity(entity).remove::<LoadingMesh>();
+            continue;
+          }
+        }
+      }
+      LoadingMesh::Mesh(handle) => {
+        if raw_meshes.get(handle).is_none() {
+          continue; // Still loading.
+        }
+        handle.clone()
+      }
+    };
+
+    commands.entity(entity)
+      .remove::<LoadingMesh>()
+      .insert(LoadedMesh(mesh));
+  }
+}
+
+/// Builds a `CustomMaterial`'s bindings from `material_type`'s schema, so every declared
+/// slot is populated (with a fallback where needed) instead of leaving holes.
+fn sync_material_resources(material: &mut CustomMaterial, material_type: &MaterialType, asset_server: &AssetServer, settings: &MaterialSettings) {
+  for (idx, (name, resource_type)) in material_type.resource_types.iter().enumerate() {
+    if *resource_type == MaterialResourceType::Time {
+      // Filled in every frame by `update_time_uniforms`; it just needs the
+      // slot claimed up front.
+      material.insert(idx, name, 0.0f32);
+      continue;
+    }
+    match settings.resources.get(name) {
+      Some(resource) if resource.matches(resource_type) =>
+        insert_material_resource(material, asset_server, idx, name, resource),
+      Some(resource) => {
+        warn!(
+          "MaterialType {:?}: resource {:?} is declared as {:?} but the material supplies {:?}; using a fallback",
+          material_type.name, name, resource_type, resource
+        );
+        insert_fallback_resource(material, idx, name, resource_type);
+      },
+      None => {
+        warn!(
+          "MaterialType {:?}: resource {:?} ({:?}) is missing from the material; using a fallback",
+          material_type.name, name, resource_type
+        );
+        insert_fallback_resource(material, idx, name, resource_type);
+      },
+    }
+  }
+}
+
+/// Converts a present-and-matching `.material` resource entry to its `RenderResource` type.
+fn insert_material_resource(material: &mut CustomMaterial, asset_server: &AssetServer, idx: usize, name: &str, res: &MaterialResource) {
+  match res {
+    MaterialResource::Color(color) =>
+      material.insert(idx, name, *color),
+    MaterialResource::Texture(texture) => {
+      let texture: Handle<Texture> = asset_server.load(texture.as_str());
+      material.insert(idx, name, texture);
+    },
+    MaterialResource::Float(value) =>
+      material.insert(idx, name, *value),
+    MaterialResource::Vec2(value) =>
+      material.insert(idx, name, Vec2::from(*value)),
+    MaterialResource::Vec3(value) =>
+      material.insert(idx, name, Vec3::from(*value)),
+    MaterialResource::Vec4(value) =>
+      material.insert(idx, name, Vec4::from(*value)),
+    MaterialResource::Int(value) =>
+      material.insert(idx, name, *value),
+  }
+}
+
+/// A type-correct placeholder for a resource slot the `.material` file didn't supply.
+fn insert_fallback_resource(material: &mut CustomMaterial, idx: usize, name: &str, resource_type: &MaterialResourceType) {
+  match resource_type {
+    MaterialResourceType::Color => material.insert(idx, name, Color::NONE),
+    MaterialResourceType::Texture => material.insert(idx, name, Handle::<Texture>::default()),
+    MaterialResourceType::Time => material.insert(idx, name, 0.0f32),
+    MaterialResourceType::Float => material.insert(idx, name, 0.0f32),
+    MaterialResourceType::Vec2 => material.insert(idx, name, Vec2::ZERO),
+    MaterialResourceType::Vec3 => material.insert(idx, name, Vec3::ZERO),
+    MaterialResourceType::Vec4 => material.insert(idx, name, Vec4::ZERO),
+    MaterialResourceType::Int => material.insert(idx, name, 0i32),
+  }
+}
+
 fn spawn_object(
-  query: Query<(Entity, &LoadedPipeline, &ObjectAsset)>,
+  query: Query<(Entity, &LoadedPipeline, &ObjectAsset, &LoadedMesh, &Handle<MaterialType>)>,
   mut commands: Commands,
   asset_server: Res<AssetServer>,
-  mut meshes: ResMut<Assets<Mesh>>,
+  material_types: Res<Assets<MaterialType>>,
   mut materials: ResMut<Assets<CustomMaterial>>,
 ) {
-  for (entity, loaded, obj) in query.iter() {
+  for (entity, loaded, obj, loaded_mesh, material_type) in query.iter() {
     eprintln!("Shader pipeline is loaded: {:#?}", obj);
 
     // Create a new material
     let mut material = CustomMaterial::new();
-    for (key, res) in &obj.material.resources {
-      match res {
-        MaterialResource::Color(color) =>
-          material.insert(key, *color),
-        MaterialResource::Texture(texture) => {
-          let texture: Handle<Texture> = asset_server.load(texture.as_str());
-          material.insert(key, texture);
-        },
-      }
+    if let Some(material_type) = material_types.get(material_type) {
+      sync_material_resources(&mut material, material_type, &asset_server, &obj.material);
     }
     let material = materials.add(material);
 
-    commands.entity(entity)
+    let mut entity = commands.entity(entity);
+    entity
       .remove::<LoadedPipeline>()
+      .remove::<LoadedMesh>()
       .insert_bundle(MeshBundle {
-        mesh: meshes.add(obj.shape.mesh()),
+        mesh: loaded_mesh.0.clone(),
         render_pipelines: RenderPipelines::from_pipelines(vec![
           loaded.render_pipeline.clone()
         ]),
@@ -318,18 +736,34 @@ fn spawn_object(
         ..Default::default()
       })
       .insert(material);
+    if let Some(instance_data) = InstanceData::from_object(obj) {
+      entity.insert(instance_data);
+    }
+  }
+}
+
+fn update_mesh_handle(
+  mut query: Query<(Entity, &LoadedMesh, &mut Handle<Mesh>), With<UpdatingMesh>>,
+  mut commands: Commands,
+) {
+  for (entity, loaded_mesh, mut mesh_handle) in query.iter_mut() {
+    *mesh_handle = loaded_mesh.0.clone();
+    commands.entity(entity)
+      .remove::<LoadedMesh>()
+      .remove::<UpdatingMesh>();
   }
 }
 
 fn update_objects(
-  mut query: Query<(Entity, &mut ObjectAsset, &Handle<ObjectAsset>, &Handle<Mesh>, &mut Transform, &Handle<CustomMaterial>), With<UpdateObject>>,
+  mut query: Query<(Entity, &mut ObjectAsset, &Handle<ObjectAsset>, &mut Handle<Mesh>, &mut Transform, &Handle<CustomMaterial>, &Handle<MaterialType>), With<UpdateObject>>,
   objects: Res<Assets<ObjectAsset>>,
+  material_types: Res<Assets<MaterialType>>,
   mut materials: ResMut<Assets<CustomMaterial>>,
   mut meshes: ResMut<Assets<Mesh>>,
   mut commands: Commands,
   asset_server: Res<AssetServer>,
 ) {
-  for (entity, mut obj, handle, mesh, mut transform, material) in query.iter_mut() {
+  for (entity, mut obj, handle, mut mesh, mut transform, material, material_type) in query.iter_mut() {
     if let Some(new_obj) = objects.get(handle) {
       // Moved.
       if new_obj.translation != obj.translation {
@@ -340,16 +774,10 @@ fn update_objects(
       // Material changed.
       if new_obj.material != obj.material {
         info!("Update material: {:?}", new_obj.material);
-        if let Some(material) = materials.get_mut(material) {
-          for (key, res) in &new_obj.material.resources {
-            match res {
-              MaterialResource::Color(color) =>
-                material.insert(key, *color),
-              MaterialResource::Texture(texture) => {
-                let texture: Handle<Texture> = asset_server.load(texture.as_str());
-                material.insert(key, texture);
-              },
-            }
+        if let Some(material_type) = material_types.get(material_type) {
+          material_type.validate(&new_obj.material);
+          if let Some(material) = materials.get_mut(material) {
+            sync_material_resources(material, material_type, &asset_server, &new_obj.material);
           }
         }
       }
@@ -357,8 +785,35 @@ fn update_objects(
       // Shape changed.
       if new_obj.shape != obj.shape {
         info!("Update shape: {:?}", new_obj.shape);
-        if let Some(mesh) = meshes.get_mut(mesh) {
-          *mesh = new_obj.shape.mesh();
+        match new_obj.shape.loading_mesh(&asset_server) {
+          Some(loading_mesh) => {
+            // GLTF/mesh-file shapes need to finish loading before the
+            // `Handle<Mesh>` can be swapped; `update_mesh_handle` does that
+            // once `LoadingMesh` resolves.
+            commands.entity(entity)
+              .insert(loading_mesh)
+              .insert(UpdatingMesh);
+          }
+          None => {
+            if let Some(new_mesh) = new_obj.shape.mesh() {
+              // Allocate a fresh mesh and swap the handle, rather than
+              // mutating whatever `mesh` currently points at: for
+              // `Gltf`/`Mesh` shapes that handle may be a shared asset (e.g.
+              // another entity pointing at the same gltf path+mesh_index),
+              // and mutating it in place would corrupt every other user.
+              *mesh = meshes.add(new_mesh);
+            }
+          }
+        }
+      }
+
+      // Instances changed.
+      if new_obj.instances != obj.instances || new_obj.instance_colors != obj.instance_colors {
+        info!("Update instances: {} instance(s)", new_obj.instances.len());
+        let mut entity = commands.entity(entity);
+        match InstanceData::from_object(new_obj) {
+          Some(instance_data) => { entity.insert(instance_data); },
+          None => { entity.remove::<InstanceData>(); },
         }
       }
 
@@ -369,12 +824,53 @@ fn update_objects(
   }
 }
 
+/// Refreshes the `Time` resource slot, if declared, on every `CustomMaterial` each frame.
+fn update_time_uniforms(
+  time: Res<Time>,
+  material_types: Res<Assets<MaterialType>>,
+  mut materials: ResMut<Assets<CustomMaterial>>,
+  query: Query<(&Handle<MaterialType>, &Handle<CustomMaterial>)>,
+) {
+  let seconds = time.seconds_since_startup() as f32;
+  for (material_type, material) in query.iter() {
+    let material_type = match material_types.get(material_type) {
+      Some(material_type) => material_type,
+      None => continue,
+    };
+    for (idx, (name, resource_type)) in material_type.resource_types.iter().enumerate() {
+      if *resource_type == MaterialResourceType::Time {
+        if let Some(material) = materials.get_mut(material) {
+          material.insert(idx, name, seconds);
+        }
+      }
+    }
+  }
+}
+
+/// Kicks off loading (material type + mesh) for an `ObjectAsset` already on
+/// `entity`. Shared by `watch_objects` and blueprint instancing.
+fn start_loading_object(entity: &mut EntityCommands, asset_server: &AssetServer, meshes: &mut Assets<Mesh>, obj: &ObjectAsset) {
+  // Need to make sure the shaders are loaded before creating the pipeline.
+  entity.insert(obj.material.loading(asset_server));
+  // Need to make sure the mesh (built-in or external) is ready before spawning.
+  match obj.shape.loading_mesh(asset_server) {
+    Some(loading_mesh) => {
+      entity.insert(loading_mesh);
+    }
+    None => {
+      let mesh = obj.shape.mesh().expect("non-async shapes always build a mesh");
+      entity.insert(LoadedMesh(meshes.add(mesh)));
+    }
+  }
+}
+
 fn watch_objects(
   mut query: Query<(Entity, &Handle<ObjectAsset>)>,
   objects: Res<Assets<ObjectAsset>>,
   mut events: EventReader<AssetEvent<ObjectAsset>>,
   mut commands: Commands,
   asset_server: Res<AssetServer>,
+  mut meshes: ResMut<Assets<Mesh>>,
 ) {
   for event in events.iter() {
     let (is_create, handle) = match event {
@@ -391,10 +887,9 @@ fn watch_objects(
         if obj_handle != handle { continue; }
         if is_create {
           info!("Loaded object: {:#?}", obj);
-          // Need to make sure the shaders are loaded before creating the pipeline.
-          commands.entity(entity)
-            .insert(obj.clone())
-            .insert(obj.material.loading(&asset_server));
+          let mut entity = commands.entity(entity);
+          entity.insert(obj.clone());
+          start_loading_object(&mut entity, &asset_server, &mut meshes, obj);
         } else {
           commands.entity(entity)
             .insert(UpdateObject);
@@ -404,6 +899,118 @@ fn watch_objects(
   }
 }
 
+/// Spawns all children of a blueprint as fresh entities, returning their ids to parent.
+fn spawn_blueprint_children(
+  commands: &mut Commands,
+  asset_server: &AssetServer,
+  meshes: &mut Assets<Mesh>,
+  blueprint: &SceneBlueprint,
+  root_translation: Vec3,
+) -> Vec<Entity> {
+  blueprint.children.iter().map(|child| {
+    let obj = child.object_asset(root_translation);
+    let mut entity = commands.spawn();
+    entity.insert(obj.clone());
+    start_loading_object(&mut entity, asset_server, meshes, &obj);
+    for component in &child.extra {
+      component.insert(&mut entity);
+    }
+    entity.id()
+  }).collect()
+}
+
+/// Spawns a brand new `SceneBlueprint` instance: a parent entity plus all its children.
+///
+/// The root gets the same `Handle<SceneBlueprint>` as a file-spawned root, so
+/// `watch_blueprints` respawns this stamped copy too when the asset reloads.
+fn spawn_blueprint_instance(
+  commands: &mut Commands,
+  asset_server: &AssetServer,
+  meshes: &mut Assets<Mesh>,
+  handle: &Handle<SceneBlueprint>,
+  blueprint: &SceneBlueprint,
+  transform: Transform,
+) -> Entity {
+  info!("Instancing blueprint: {:?}", blueprint.name);
+  let mut root = commands.spawn_bundle((handle.clone(), transform, GlobalTransform::default()));
+  for component in &blueprint.extra {
+    component.insert(&mut root);
+  }
+  let parent = root.id();
+  let children = spawn_blueprint_children(commands, asset_server, meshes, blueprint, transform.translation);
+  commands.entity(parent).push_children(&children);
+  parent
+}
+
+/// A `Command` that instances a `SceneBlueprint` at an arbitrary transform.
+pub struct SpawnBlueprintInstance {
+  pub blueprint: Handle<SceneBlueprint>,
+  pub transform: Transform,
+}
+
+impl Command for SpawnBlueprintInstance {
+  fn write(self, world: &mut World) {
+    let blueprint = match world.get_resource::<Assets<SceneBlueprint>>()
+      .and_then(|blueprints| blueprints.get(&self.blueprint))
+    {
+      Some(blueprint) => blueprint.clone(),
+      None => {
+        eprintln!("SpawnBlueprintInstance: blueprint is not loaded yet");
+        return;
+      }
+    };
+    world.resource_scope(|world, asset_server: Mut<AssetServer>| {
+      world.resource_scope(|world, mut meshes: Mut<Assets<Mesh>>| {
+        let mut queue = CommandQueue::default();
+        let mut commands = Commands::new(&mut queue, world);
+        spawn_blueprint_instance(&mut commands, &asset_server, &mut meshes, &self.blueprint, &blueprint, self.transform);
+        queue.apply(world);
+      });
+    });
+  }
+}
+
+/// Spawns/respawns a blueprint's children whenever its `.blueprint` file is (re)loaded.
+fn watch_blueprints(
+  mut query: Query<(Entity, &Handle<SceneBlueprint>, &Transform, Option<&Children>)>,
+  blueprints: Res<Assets<SceneBlueprint>>,
+  mut events: EventReader<AssetEvent<SceneBlueprint>>,
+  mut commands: Commands,
+  asset_server: Res<AssetServer>,
+  mut meshes: ResMut<Assets<Mesh>>,
+) {
+  for event in events.iter() {
+    let handle = match event {
+      AssetEvent::Created { handle } | AssetEvent::Modified { handle } => handle,
+      _ => continue,
+    };
+    let blueprint = match blueprints.get(handle) {
+      Some(blueprint) => blueprint,
+      None => continue,
+    };
+    for (entity, blueprint_handle, transform, children) in query.iter_mut() {
+      if blueprint_handle != handle { continue; }
+      info!("(Re)spawning blueprint: {:?}", blueprint.name);
+      if let Some(children) = children {
+        let old_children: Vec<Entity> = children.iter().copied().collect();
+        // despawn_recursive doesn't prune the parent's own Children list, so
+        // without this the push_children below would append onto dangling
+        // entity ids left over from the previous load.
+        commands.entity(entity).remove_children(&old_children);
+        for &child in &old_children {
+          commands.entity(child).despawn_recursive();
+        }
+      }
+      let mut root = commands.entity(entity);
+      for component in &blueprint.extra {
+        component.insert(&mut root);
+      }
+      let new_children = spawn_blueprint_children(&mut commands, &asset_server, &mut meshes, blueprint, transform.translation);
+      commands.entity(entity).push_children(&new_children);
+    }
+  }
+}
+
 fn setup(
   mut render_graph: ResMut<RenderGraph>,
 ) {
@@ -419,6 +1026,16 @@ fn setup(
   render_graph
     .add_node_edge("custom_material", base::node::MAIN_PASS)
     .unwrap();
+
+  // Add the InstanceBufferNode to upload per-instance data for GPU-instanced
+  // objects; `apply_instance_draw_commands` binds the uploaded buffers.
+  render_graph.add_system_node(
+    "instance_buffer",
+    InstanceBufferNode::default(),
+  );
+  render_graph
+    .add_node_edge("instance_buffer", base::node::MAIN_PASS)
+    .unwrap();
 }
 
 /// CustomMaterialPlugin - For loading custom materials from files.
@@ -434,14 +1051,125 @@ impl Plugin for CustomMaterialPlugin {
       .add_plugin(RonAssetPlugin::<MaterialType>::new(&["material_type"]))
       // load materials from .material files.
       .add_plugin(RonAssetPlugin::<MaterialSettings>::new(&["material"]))
+      // load scene blueprints from .blueprint files.
+      .add_plugin(RonAssetPlugin::<SceneBlueprint>::new(&["blueprint"]))
       .add_startup_system(setup.system())
       .add_system(loading_material_type.system())
       .add_system(loading_pipeline.system())
+      .add_system(loading_mesh.system())
       .add_system(spawn_object.system())
       .add_system(watch_objects.system())
+      .add_system(watch_blueprints.system())
       .add_system(update_objects.system())
+      .add_system(update_mesh_handle.system())
+      .add_system(update_time_uniforms.system())
+      .init_resource::<InstanceBuffers>()
+      .add_system_to_stage(
+        stage::DRAW,
+        // Must run after bevy's own mesh-draw system, which (re)populates
+        // `Draw` with the default `DrawIndexed`/`SetVertexBuffer` commands
+        // every frame; otherwise this override is a coin flip depending on
+        // unspecified ordering within `stage::DRAW`.
+        apply_instance_draw_commands.system().after(draw_render_pipelines_system),
+      )
       .add_asset::<CustomMaterial>()
       ;
 
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn test_material_type(resource_types: &[(&str, MaterialResourceType)]) -> MaterialType {
+    MaterialType {
+      name: "test".into(),
+      pipeline: MaterialPipeline { vertex: "test.vert".into(), fragment: None },
+      resource_types: resource_types.iter()
+        .map(|(name, ty)| (name.to_string(), ty.clone()))
+        .collect(),
+    }
+  }
+
+  #[test]
+  fn material_resource_matches_checks_variant_not_value() {
+    assert!(MaterialResource::Color(Color::RED).matches(&MaterialResourceType::Color));
+    assert!(!MaterialResource::Color(Color::RED).matches(&MaterialResourceType::Float));
+    assert!(MaterialResource::Float(1.0).matches(&MaterialResourceType::Float));
+    assert!(!MaterialResource::Int(1).matches(&MaterialResourceType::Vec3));
+  }
+
+  #[test]
+  fn validate_does_not_panic_on_missing_or_extra_resources() {
+    let material_type = test_material_type(&[("color", MaterialResourceType::Color)]);
+    let mut resources = IndexMap::new();
+    resources.insert("unrelated".into(), MaterialResource::Float(1.0));
+    let settings = MaterialSettings { material_type: "test.material_type".into(), resources };
+    material_type.validate(&settings);
+  }
+
+  fn test_object(instances: Vec<[f32; 3]>, instance_colors: Vec<Color>) -> ObjectAsset {
+    ObjectAsset {
+      shape: ObjectShape::Cube(1.0),
+      translation: [0.0, 0.0, 0.0],
+      material: MaterialSettings { material_type: "test.material_type".into(), resources: IndexMap::new() },
+      instances,
+      instance_colors,
+    }
+  }
+
+  #[test]
+  fn instance_data_from_object_is_none_without_instances() {
+    let obj = test_object(vec![], vec![]);
+    assert!(InstanceData::from_object(&obj).is_none());
+  }
+
+  #[test]
+  fn instance_data_from_object_packs_one_row_per_instance() {
+    let obj = test_object(vec![[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]], vec![Color::RED]);
+    let instance_data = InstanceData::from_object(&obj).unwrap();
+    assert_eq!(instance_data.instance_count(), 2);
+    assert_eq!(instance_data.buffer.len(), 2 * INSTANCE_DATA_STRIDE * 4);
+  }
+
+  #[test]
+  fn instance_data_from_object_defaults_missing_colors_to_white() {
+    let obj = test_object(vec![[0.0, 0.0, 0.0], [1.0, 1.0, 1.0]], vec![Color::RED]);
+    let instance_data = InstanceData::from_object(&obj).unwrap();
+    // Second instance's color is missing from `instance_colors`; its row
+    // (starting after the first instance's full stride) should be white.
+    let color_offset = (INSTANCE_DATA_STRIDE + 4) * 4;
+    let r = f32::from_le_bytes(instance_data.buffer[color_offset..color_offset + 4].try_into().unwrap());
+    assert_eq!(r, Color::WHITE.r());
+    // Red and white share `r`; `g` tells them apart.
+    let g = f32::from_le_bytes(instance_data.buffer[color_offset + 4..color_offset + 8].try_into().unwrap());
+    assert_eq!(g, Color::WHITE.g());
+  }
+
+  #[test]
+  fn remove_children_clears_parent_before_despawn_recursive() {
+    use bevy::hierarchy::BuildWorldChildren;
+
+    // Regression test for watch_blueprints: despawn_recursive alone doesn't
+    // prune the parent's own Children list, so push_children for the new
+    // batch would otherwise append onto dangling entity ids.
+    let mut world = World::new();
+    let parent = world.spawn().id();
+    let old_a = world.spawn().id();
+    let old_b = world.spawn().id();
+    world.entity_mut(parent).push_children(&[old_a, old_b]);
+
+    let old_children: Vec<Entity> = world.get::<Children>(parent).unwrap().iter().copied().collect();
+    world.entity_mut(parent).remove_children(&old_children);
+    for child in old_children {
+      world.despawn(child);
+    }
+
+    let new_child = world.spawn().id();
+    world.entity_mut(parent).push_children(&[new_child]);
+
+    let children: Vec<Entity> = world.get::<Children>(parent).unwrap().iter().copied().collect();
+    assert_eq!(children, vec![new_child]);
+  }
+}